@@ -22,42 +22,112 @@
 #![feature(slicing_syntax)]
 #![warn(missing_docs)]
 
-extern crate "rustc-serialize" as rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate bincode;
 
 use std::borrow::ToOwned;
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
+use std::f64::NEG_INFINITY;
+use std::fmt;
 use std::hash::Hash;
 use std::io::{BufferedReader, File, InvalidInput, IoError, IoResult};
+use std::io::process::Command;
 use std::iter::Map;
-use std::rand::{Rng, task_rng};
+use std::num::Float;
+use std::rand::{Rng, SeedableRng, StdRng, task_rng};
 use std::rc::Rc;
-use rustc_serialize::{Decodable, Encodable};
-use rustc_serialize::json::{Decoder, DecoderError, Encoder, decode, encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::ser::SerializeStruct;
+
+/// An upper bound on how many tokens `generate_ranked` will emit along any one beam before giving
+/// up on it, so a chain with a cycle that the sampled data never escapes can't run forever.
+const MAX_RANKED_LEN: uint = 256;
+
+/// The default `max_len` used by `GenerateOptions::default`, chosen independently of
+/// `MAX_RANKED_LEN`: beam-search depth and a single random walk's default length guard are
+/// unrelated bounds and have no reason to move together.
+const DEFAULT_MAX_GENERATE_LEN: uint = 256;
+
+/// Configuration for `Chain::generate_with`.
+pub struct GenerateOptions {
+    /// The maximum number of tokens to emit, even if the chain has not reached a terminal state.
+    /// Guarantees that generation terminates even on a chain whose cycles the fed data never
+    /// escapes.
+    pub max_len: uint,
+    /// When `Some`, seeds a dedicated RNG so that repeated calls with the same seed against the
+    /// same chain produce the same output. When `None`, uses the thread's default RNG, the same
+    /// as `generate`.
+    pub rng_seed: Option<u32>,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> GenerateOptions {
+        GenerateOptions { max_len: DEFAULT_MAX_GENERATE_LEN, rng_seed: None }
+    }
+}
 
-/// A generic [Markov chain](https://en.wikipedia.org/wiki/Markov_chain) for almost any type. This 
-/// uses HashMaps internally, and so Eq and Hash are both required.
-#[deriving(RustcEncodable, RustcDecodable, PartialEq, Show)]
+/// A generic [Markov chain](https://en.wikipedia.org/wiki/Markov_chain) for almost any type. This
+/// uses HashMaps internally, and so Eq and Hash are both required. The chain is conditioned on the
+/// `order` previous tokens rather than just the single previous one, which lets longer-range
+/// structure in the fed data show up in generated output.
 pub struct Chain<T: Eq + Hash> {
-    map: HashMap<Option<Rc<T>>, HashMap<Option<Rc<T>>, uint>>,
+    map: HashMap<Vec<Option<Rc<T>>>, HashMap<Option<Rc<T>>, uint>>,
+    order: uint,
+    // Shell command templates used by `feed_path` to extract text from files, keyed by file
+    // extension. Only meaningful for `Chain<String>`; see `register_loader`.
+    loaders: HashMap<String, String>,
+}
+
+// `loaders` is pure extraction configuration, not learned chain state, so it's deliberately left
+// out of both equality and the debug representation: two chains fed the same data should compare
+// equal (and print the same) regardless of what loaders either has registered, and a chain should
+// still equal itself after a save/load round trip even though deserializing always resets
+// `loaders` to `default_loaders()`.
+
+impl<T: Eq + Hash> PartialEq for Chain<T> {
+    fn eq(&self, other: &Chain<T>) -> bool {
+        self.map == other.map && self.order == other.order
+    }
+}
+
+impl<T: Eq + Hash + fmt::Show> fmt::Show for Chain<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Chain {{ map: {}, order: {} }}", self.map, self.order)
+    }
 }
 
 impl<T: Eq + Hash> Chain<T> {
-    /// Constructs a new Markov chain. 
+    /// Constructs a new Markov chain, conditioned on a single previous token (order 1).
     pub fn new() -> Chain<T> {
+        Chain::of_order(1)
+    }
+
+    /// Constructs a new Markov chain conditioned on the previous `order` tokens. An `order` of 1
+    /// behaves like `Chain::new()`. `order` is clamped to a minimum of 1: a 0th-order chain
+    /// would condition each token on no context at all, which `generate_from_token`'s seeded
+    /// window has no room to represent.
+    pub fn of_order(order: uint) -> Chain<T> {
+        let order = cmp::max(order, 1);
         Chain {
             map: {
                 let mut map = HashMap::new();
-                map.insert(None, HashMap::new());
+                map.insert(Vec::from_elem(order, None), HashMap::new());
                 map
-            }
+            },
+            order: order,
+            loaders: default_loaders(),
         }
     }
 
     /// Determines whether or not the chain is empty. A chain is considered empty if nothing has
     /// been fed into it.
     pub fn is_empty(&self) -> bool {
-        let start: Option<Rc<T>> = None;
+        let start: Vec<Option<Rc<T>>> = Vec::from_elem(self.order, None);
         self.map[start].is_empty()
     }
 
@@ -66,18 +136,15 @@ impl<T: Eq + Hash> Chain<T> {
     /// tokens to be fed into the chain.
     pub fn feed(&mut self, tokens: Vec<T>) -> &mut Chain<T> {
         if tokens.len() == 0 { return self }
-        let mut toks = Vec::new();
+        let mut toks = Vec::from_elem(self.order, None);
+        toks.extend(tokens.into_iter().map(|token| Some(Rc::new(token))));
         toks.push(None);
-        toks.extend(tokens.into_iter().map(|token| {
-            let rc = Rc::new(token);
-            if !self.map.contains_key(&Some(rc.clone())) {
-                self.map.insert(Some(rc.clone()), HashMap::new());
+        for window in toks.windows(self.order + 1) {
+            let key = window[0..self.order].to_vec();
+            if !self.map.contains_key(&key) {
+                self.map.insert(key.clone(), HashMap::new());
             }
-            Some(rc)
-        }));
-        toks.push(None);
-        for p in toks.windows(2) {
-            (&mut self.map[p[0]]).add(p[1].clone());
+            (&mut self.map[key]).add(window[self.order].clone());
         }
         self
     }
@@ -86,14 +153,9 @@ impl<T: Eq + Hash> Chain<T> {
     /// length of the generated collection, and n is the number of possible states from a given
     /// state.
     pub fn generate(&self) -> Vec<Rc<T>> {
-        let mut ret = Vec::new();
-        let mut curs = None;
-        loop {
-            curs = self.map[curs].next();
-            if curs.is_none() { break }
-            ret.push(curs.clone().unwrap());    
-        }
-        ret
+        let mut rng = task_rng();
+        let window = Vec::from_elem(self.order, None);
+        self.walk(&mut rng, window, Vec::new(), None)
     }
 
     /// Generates a collection of tokens from the chain, starting with the given token. This
@@ -102,17 +164,109 @@ impl<T: Eq + Hash> Chain<T> {
     /// found.
     pub fn generate_from_token(&self, token: T) -> Vec<Rc<T>> {
         let token = Rc::new(token);
-        if !self.map.contains_key(&Some(token.clone())) { return Vec::new() }
-        let mut ret = vec![token.clone()];
-        let mut curs = Some(token);
+        let mut window: Vec<Option<Rc<T>>> = Vec::from_elem(self.order - 1, None);
+        window.push(Some(token.clone()));
+        if !self.map.contains_key(&window) { return Vec::new() }
+        let mut rng = task_rng();
+        self.walk(&mut rng, window, vec![token], None)
+    }
+
+    /// Generates a collection of tokens from the chain using the given `GenerateOptions`. Unlike
+    /// `generate`, this caps the number of emitted tokens at `options.max_len` (guaranteeing
+    /// termination even on chains with no reachable terminal state), and, if `options.rng_seed`
+    /// is set, samples from a seeded RNG so the output is reproducible.
+    pub fn generate_with(&self, options: GenerateOptions) -> Vec<Rc<T>> {
+        let window = Vec::from_elem(self.order, None);
+        match options.rng_seed {
+            Some(seed) => {
+                let mut rng: StdRng = SeedableRng::from_seed(&[seed as uint][]);
+                self.walk(&mut rng, window, Vec::new(), Some(options.max_len))
+            }
+            None => {
+                let mut rng = task_rng();
+                self.walk(&mut rng, window, Vec::new(), Some(options.max_len))
+            }
+        }
+    }
+
+    /// Walks the chain from `window`, appending sampled tokens to `ret` until a terminal state is
+    /// reached or, if `max_len` is `Some`, until `ret` has that many tokens.
+    fn walk<R: Rng>(&self, rng: &mut R, mut window: Vec<Option<Rc<T>>>, mut ret: Vec<Rc<T>>,
+                    max_len: Option<uint>) -> Vec<Rc<T>> {
         loop {
-            curs = self.map[curs].next();
-            if curs.is_none() { break }
-            ret.push(curs.clone().unwrap());    
+            if let Some(max_len) = max_len {
+                if ret.len() >= max_len { break }
+            }
+            let next = self.map[window].next(rng);
+            if next.is_none() { break }
+            ret.push(next.clone().unwrap());
+            window.remove(0);
+            window.push(next);
         }
         ret
     }
 
+    /// Generates the `k` highest-probability token sequences the chain can produce, each paired
+    /// with its log-probability. This is a beam search over the transition counts rather than a
+    /// random walk: unlike `generate`, it is deterministic and biased towards the chain's most
+    /// common paths rather than a representative sample of them. Uses a beam width of `4 * k`;
+    /// see `generate_ranked_with_beam` to control that directly.
+    pub fn generate_ranked(&self, k: uint) -> Vec<(Vec<Rc<T>>, f64)> {
+        self.generate_ranked_with_beam(k, 4 * k)
+    }
+
+    /// Like `generate_ranked`, but with an explicit beam width `beam_width` (the number of
+    /// partial sequences kept alive at each step). A wider beam considers more of the chain's
+    /// state space at the cost of more work. A beam narrower than `k` may return fewer than `k`
+    /// results.
+    pub fn generate_ranked_with_beam(&self, k: uint, beam_width: uint) -> Vec<(Vec<Rc<T>>, f64)> {
+        struct Beam<T: Eq + Hash> {
+            window: Vec<Option<Rc<T>>>,
+            sequence: Vec<Rc<T>>,
+            score: f64,
+        }
+
+        let mut beam = vec![Beam {
+            window: Vec::from_elem(self.order, None),
+            sequence: Vec::new(),
+            score: 0f64,
+        }];
+        let mut finished: Vec<(Vec<Rc<T>>, f64)> = Vec::new();
+        let mut steps = 0u;
+        while !beam.is_empty() && steps < MAX_RANKED_LEN {
+            steps += 1;
+            let mut candidates = Vec::new();
+            for entry in beam.into_iter() {
+                let succs = match self.map.get(&entry.window) {
+                    Some(succs) => succs,
+                    None => continue,
+                };
+                let total: uint = succs.values().fold(0u, |a, &b| a + b);
+                if total == 0 { continue }
+                for (succ, &count) in succs.iter() {
+                    let score = entry.score + (count as f64 / total as f64).ln();
+                    match *succ {
+                        None => finished.push((entry.sequence.clone(), score)),
+                        Some(ref token) => {
+                            let mut window = entry.window.clone();
+                            window.remove(0);
+                            window.push(Some(token.clone()));
+                            let mut sequence = entry.sequence.clone();
+                            sequence.push(token.clone());
+                            candidates.push(Beam { window: window, sequence: sequence, score: score });
+                        }
+                    }
+                }
+            }
+            candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+            candidates.truncate(beam_width);
+            beam = candidates;
+        }
+        finished.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        finished.truncate(k);
+        finished
+    }
+
     /// Produces an infinite iterator of generated token collections.
     pub fn iter(&self) -> InfiniteChainIterator<T> {
         InfiniteChainIterator { chain: self }
@@ -124,12 +278,12 @@ impl<T: Eq + Hash> Chain<T> {
     }
 }
 
-impl<T: Decodable<Decoder, DecoderError> + Eq + Hash> Chain<T> {
+impl<T: Deserialize + Eq + Hash + Clone> Chain<T> {
     /// Loads a chain from a JSON file at the specified path.
     pub fn load(path: &Path) -> IoResult<Chain<T>> {
         let mut file = try!(File::open(path));
         let data = try!(file.read_to_string());
-        decode(data[]).map_err(|e| IoError {
+        serde_json::from_str(data[]).map_err(|e| IoError {
             kind: InvalidInput,
             desc: "Decoder error",
             detail: Some(e.to_string()),
@@ -140,23 +294,163 @@ impl<T: Decodable<Decoder, DecoderError> + Eq + Hash> Chain<T> {
     pub fn load_utf8(path: &str) -> IoResult<Chain<T>> {
         Chain::load(&Path::new(path))
     }
+
+    /// Loads a chain from the compact binary format written by `save_binary`. This is
+    /// substantially smaller and faster to round-trip than the JSON form for large chains.
+    pub fn load_binary(path: &Path) -> IoResult<Chain<T>> {
+        let mut file = try!(File::open(path));
+        let data = try!(file.read_to_end());
+        bincode::deserialize(data[]).map_err(|e| IoError {
+            kind: InvalidInput,
+            desc: "Decoder error",
+            detail: Some(e.to_string()),
+        })
+    }
+
+    /// Loads a chain from the compact binary format using a string path.
+    pub fn load_binary_utf8(path: &str) -> IoResult<Chain<T>> {
+        Chain::load_binary(&Path::new(path))
+    }
 }
 
-impl<'a, T: Encodable<Encoder<'a>, IoError> + Eq + Hash> Chain<T> {
+impl<T: Serialize + Eq + Hash> Chain<T> {
     /// Saves a chain to a JSON file at the specified path.
     pub fn save(&self, path: &Path) -> IoResult<()> {
         let mut f = File::create(path);
-        f.write_str(encode(self)[])
+        f.write_str(serde_json::to_string(self).unwrap()[])
     }
 
     /// Saves a chain to a JSON file using a string path.
     pub fn save_utf8(&self, path: &str) -> IoResult<()> {
         self.save(&Path::new(path))
     }
+
+    /// Saves a chain to a compact binary file at the specified path. Prefer this over `save`
+    /// for large chains; it produces a much smaller file and is faster to read back with
+    /// `load_binary`.
+    pub fn save_binary(&self, path: &Path) -> IoResult<()> {
+        let mut f = File::create(path);
+        let data = bincode::serialize(self).unwrap();
+        f.write(data[])
+    }
+
+    /// Saves a chain to a compact binary file using a string path.
+    pub fn save_binary_utf8(&self, path: &str) -> IoResult<()> {
+        self.save_binary(&Path::new(path))
+    }
+}
+
+/// The on-disk representation of a `Chain`. Keys and successors are stored as owned tokens
+/// rather than `Rc<T>`, since reference-counting is purely an in-memory sharing optimization and
+/// would otherwise bloat the serialized form with bookkeeping that means nothing once reloaded.
+#[derive(Serialize, Deserialize)]
+struct ChainRepr<T> {
+    order: uint,
+    entries: Vec<(Vec<Option<T>>, Vec<(Option<T>, uint)>)>,
+}
+
+impl<T: Eq + Hash + Serialize> Serialize for Chain<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = try!(serializer.serialize_struct("Chain", 2));
+        try!(state.serialize_field("order", &self.order));
+        let entries: Vec<_> = self.map.iter().map(|(key, succs)| {
+            let key = key.iter().map(|tok| tok.as_ref().map(|rc| &**rc)).collect();
+            let succs = succs.iter().map(|(tok, count)| (tok.as_ref().map(|rc| &**rc), *count)).collect();
+            (key, succs)
+        }).collect();
+        try!(state.serialize_field("entries", &entries));
+        state.end()
+    }
+}
+
+impl<T: Eq + Hash + Clone + Deserialize> Deserialize for Chain<T> {
+    /// Rebuilds a `Chain` from its serialized representation. Because `ChainRepr` stores owned
+    /// tokens rather than `Rc<T>`, equal tokens are re-interned through a `HashMap<T, Rc<T>>` so
+    /// the loaded chain shares identical tokens the same way a freshly-fed one would.
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<Chain<T>, D::Error> {
+        let repr = try!(ChainRepr::deserialize(deserializer));
+        let mut interned: HashMap<T, Rc<T>> = HashMap::new();
+        let mut map = HashMap::new();
+        for (key, succs) in repr.entries.into_iter() {
+            let key = key.into_iter().map(|tok| tok.map(|t| Chain::intern(&mut interned, t))).collect();
+            let mut inner = HashMap::new();
+            for (tok, count) in succs.into_iter() {
+                inner.insert(tok.map(|t| Chain::intern(&mut interned, t)), count);
+            }
+            map.insert(key, inner);
+        }
+        Ok(Chain { map: map, order: repr.order, loaders: default_loaders() })
+    }
+}
+
+impl<T: Eq + Hash + Clone> Chain<T> {
+    /// Looks `value` up in the interning table, returning the existing `Rc` if an equal value has
+    /// already been seen during this deserialization, or inserting and returning a new one.
+    fn intern(table: &mut HashMap<T, Rc<T>>, value: T) -> Rc<T> {
+        if let Some(rc) = table.get(&value) {
+            return rc.clone();
+        }
+        let rc = Rc::new(value.clone());
+        table.insert(value, rc.clone());
+        rc
+    }
+
+    /// Scores a caller-supplied sequence of tokens under this chain, as the sum of the
+    /// log-probabilities of each token given the `order` tokens preceding it (and of the
+    /// terminating `None` after the last one). This is `generate_ranked`'s counterpart for
+    /// sequences the chain didn't produce itself, so a caller can rerank candidates from
+    /// elsewhere. Returns negative infinity if the chain never observed some step of the
+    /// sequence.
+    pub fn sequence_probability(&self, tokens: &[T]) -> f64 {
+        let mut window: Vec<Option<Rc<T>>> = Vec::from_elem(self.order, None);
+        let mut toks: Vec<Option<Rc<T>>> = tokens.iter().cloned().map(|t| Some(Rc::new(t))).collect();
+        toks.push(None);
+        let mut score = 0f64;
+        for tok in toks.into_iter() {
+            let succs = match self.map.get(&window) {
+                Some(succs) => succs,
+                None => return NEG_INFINITY,
+            };
+            let total: uint = succs.values().fold(0u, |a, &b| a + b);
+            let count = match succs.get(&tok) {
+                Some(&count) => count,
+                None => return NEG_INFINITY,
+            };
+            score += (count as f64 / total as f64).ln();
+            window.remove(0);
+            window.push(tok);
+        }
+        score
+    }
+}
+
+/// Builds the loader registry that every `Chain` starts out with: no shell loaders registered, so
+/// `feed_path` falls back to reading the file directly (as `feed_file` does) until the caller
+/// registers one with `Chain::register_loader`.
+fn default_loaders() -> HashMap<String, String> {
+    HashMap::new()
+}
+
+/// Shell-quotes `s` for safe interpolation into a loader's `sh -c` command line: wraps it in
+/// single quotes, escaping any embedded single quote as `'\''`. Without this, a path containing
+/// shell metacharacters (spaces, `;`, `$()`, backticks, quotes) could inject arbitrary commands
+/// into the loader invocation.
+fn shell_quote(s: &str) -> String {
+    let mut quoted = String::new();
+    quoted.push('\'');
+    for c in s.chars() {
+        if c == '\'' {
+            quoted.push_str("'\\''");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push('\'');
+    quoted
 }
 
 impl Chain<String> {
-    /// Feeds a string of text into the chain.     
+    /// Feeds a string of text into the chain.
     pub fn feed_str(&mut self, string: &str) -> &mut Chain<String> {
         self.feed(string.split_str(" ").map(|s| s.to_owned()).collect())
     }
@@ -175,6 +469,37 @@ impl Chain<String> {
         self
     }
 
+    /// Registers a shell command used to extract text from files with the given extension, e.g.
+    /// `chain.register_loader("pdf", "pdftotext $1 -")`. The `$1` placeholder is replaced with
+    /// the (shell-quoted) path being fed; the command's stdout is tokenized the same way
+    /// `feed_file` tokenizes a line and fed into the chain. Registering a loader for an extension
+    /// that already has one replaces it.
+    pub fn register_loader(&mut self, ext: &str, command_template: &str) -> &mut Chain<String> {
+        self.loaders.insert(ext.to_owned(), command_template.to_owned());
+        self
+    }
+
+    /// Feeds a file into the chain, choosing how to extract its text from the file's extension.
+    /// A file whose extension has no registered loader (as is the case for `txt` by default) is
+    /// read directly, the same way `feed_file` does. A file whose extension has a registered
+    /// loader, `txt` included, is piped through that loader's shell command and its stdout is
+    /// tokenized and fed in instead.
+    pub fn feed_path(&mut self, path: &Path) -> &mut Chain<String> {
+        let command_template = path.extension_str().and_then(|ext| self.loaders.get(ext)).cloned();
+        match command_template {
+            Some(command_template) => {
+                let command = command_template.replace("$1", shell_quote(path.display().to_string()[])[]);
+                let output = Command::new("sh").arg("-c").arg(command[]).output().unwrap();
+                let text = String::from_utf8_lossy(output.output[]).into_owned();
+                self.feed(text.split([' ', '\t', '\n', '\r'][])
+                              .filter(|word| !word.is_empty())
+                              .map(|s| s.to_owned())
+                              .collect())
+            }
+            None => self.feed_file(path),
+        }
+    }
+
     /// Converts the output of generate(...) on a String chain to a single String.
     fn vec_to_string(vec: Vec<Rc<String>>) -> String {
         let mut ret = String::new();
@@ -258,8 +583,8 @@ impl<'a, T: Eq + Hash + 'a> Iterator<Vec<Rc<T>>> for InfiniteChainIterator<'a, T
 trait States<T: PartialEq> {
     /// Adds a state to this states collection.
     fn add(&mut self, token: Option<Rc<T>>);
-    /// Gets the next state from this collection of states.
-    fn next(&self) -> Option<Rc<T>>;
+    /// Gets the next state from this collection of states, sampling with the given RNG.
+    fn next<R: Rng>(&self, rng: &mut R) -> Option<Rc<T>>;
 }
 
 impl<T: Eq + Hash> States<T> for HashMap<Option<Rc<T>>, uint> {
@@ -270,12 +595,11 @@ impl<T: Eq + Hash> States<T> for HashMap<Option<Rc<T>>, uint> {
         }
     }
 
-    fn next(&self) -> Option<Rc<T>> {
+    fn next<R: Rng>(&self, rng: &mut R) -> Option<Rc<T>> {
         let mut sum = 0;
         for &value in self.values() {
             sum += value;
         }
-        let mut rng = task_rng();
         let cap = rng.gen_range(0, sum);
         sum = 0;
         for (key, &value) in self.iter() {
@@ -290,7 +614,10 @@ impl<T: Eq + Hash> States<T> for HashMap<Option<Rc<T>>, uint> {
 
 #[cfg(test)]
 mod test {
-    use super::Chain;
+    use super::{Chain, GenerateOptions};
+    use std::f64::NEG_INFINITY;
+    use std::io::File;
+    use std::io::fs::PathExtensions;
 
     #[test]
     fn new() {
@@ -298,6 +625,23 @@ mod test {
         Chain::<String>::new();
     }
 
+    #[test]
+    fn of_order() {
+        let mut chain = Chain::of_order(2);
+        chain.feed(vec![5u, 10u, 5u, 12u]);
+        let v = chain.generate_from_token(5u).map_in_place(|v| *v);
+        assert_eq!(v, vec![5u, 10u, 5u, 12u]);
+    }
+
+    #[test]
+    fn of_order_zero_is_clamped() {
+        let mut chain = Chain::of_order(0u);
+        chain.feed(vec![5u, 10u]);
+        // Would underflow `Vec::from_elem(self.order - 1, None)` in generate_from_token if
+        // order 0 were allowed through unclamped.
+        chain.generate_from_token(5u);
+    }
+
     #[test]
     fn is_empty() {
         let mut chain = Chain::new();
@@ -337,7 +681,45 @@ mod test {
     }
 
     #[test]
-    fn iter() {    
+    fn generate_with_caps_length() {
+        let mut chain = Chain::new();
+        chain.feed(vec![3u, 5u, 10u]).feed(vec![5u, 12u]);
+        let v = chain.generate_with(GenerateOptions { max_len: 1, rng_seed: Some(7) });
+        assert!(v.len() <= 1);
+    }
+
+    #[test]
+    fn generate_with_seeded_rng_is_reproducible() {
+        let mut chain = Chain::new();
+        chain.feed(vec![3u, 5u, 10u]).feed(vec![5u, 12u]);
+        let a = chain.generate_with(GenerateOptions { max_len: 10, rng_seed: Some(42) });
+        let b = chain.generate_with(GenerateOptions { max_len: 10, rng_seed: Some(42) });
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn generate_ranked() {
+        let mut chain = Chain::new();
+        chain.feed(vec![3u, 5u, 10u]).feed(vec![5u, 12u]);
+        let ranked = chain.generate_ranked(2);
+        assert_eq!(ranked.len(), 2);
+        for &(ref seq, score) in ranked.iter() {
+            let v: Vec<_> = seq.iter().map(|tok| **tok).collect();
+            assert!([vec![5u, 10u], vec![5u, 12u]].contains(&v));
+            assert_eq!(score, (0.5f64).ln() + (0.5f64).ln());
+        }
+    }
+
+    #[test]
+    fn sequence_probability() {
+        let mut chain = Chain::new();
+        chain.feed(vec![3u, 5u, 10u]).feed(vec![5u, 12u]);
+        assert_eq!(chain.sequence_probability(&[5u, 10u]), (0.5f64).ln() + (0.5f64).ln());
+        assert_eq!(chain.sequence_probability(&[9u]), NEG_INFINITY);
+    }
+
+    #[test]
+    fn iter() {
         let mut chain = Chain::new();
         chain.feed(vec![3u, 5u, 10u]).feed(vec![5u, 12u]);
         assert_eq!(chain.iter().size_hint().1, None);
@@ -409,5 +791,61 @@ mod test {
         let other_chain: Chain<String> = Chain::load_utf8("load.json").unwrap();
         assert_eq!(other_chain, chain);
     }
+
+    #[test]
+    fn save_binary() {
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats and I like dogs");
+        chain.save_binary_utf8("save.bin").unwrap();
+    }
+
+    #[test]
+    fn load_binary() {
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats and I like dogs");
+        chain.save_binary_utf8("load.bin").unwrap();
+        let other_chain: Chain<String> = Chain::load_binary_utf8("load.bin").unwrap();
+        assert_eq!(other_chain, chain);
+    }
+
+    #[test]
+    fn feed_path_without_loader_reads_directly() {
+        let path = Path::new("feed_path_direct.txt");
+        File::create(&path).write_str("I like cats and dogs").unwrap();
+        let mut chain = Chain::new();
+        chain.feed_path(&path);
+        let mut expected = Chain::new();
+        expected.feed_file(&path);
+        assert_eq!(chain, expected);
+    }
+
+    #[test]
+    fn feed_path_with_registered_loader() {
+        let path = Path::new("feed_path_loader.txt");
+        File::create(&path).write_str("I like cats and dogs").unwrap();
+        let mut chain = Chain::new();
+        chain.register_loader("txt", "cat $1");
+        chain.feed_path(&path);
+        let mut expected = Chain::new();
+        expected.feed_str("I like cats and dogs");
+        assert_eq!(chain, expected);
+    }
+
+    #[test]
+    fn feed_path_shell_quotes_the_path() {
+        // The file name below contains shell metacharacters (a single quote and a semicolon).
+        // If the loader command weren't properly quoted, substituting this path would break out
+        // of the intended argument and run an injected command instead of (or in addition to)
+        // `cat`ing the file.
+        let path = Path::new("fee'd; touch feed_path_injected.txt path.txt");
+        File::create(&path).write_str("I like cats and dogs").unwrap();
+        let mut chain = Chain::new();
+        chain.register_loader("txt", "cat $1");
+        chain.feed_path(&path);
+        let mut expected = Chain::new();
+        expected.feed_str("I like cats and dogs");
+        assert_eq!(chain, expected);
+        assert!(!Path::new("feed_path_injected.txt").exists());
+    }
 }
 